@@ -1,4 +1,6 @@
-pub use encoding::DataEncoding;
+pub use encoding::{Compression, DataEncoding, DeserializeError, SerializeError};
+
+use std::time::Duration;
 
 /// General configuration of the cache functionality.
 #[derive(Clone, Debug)]
@@ -9,11 +11,22 @@ pub struct CacheConfig {
     /// Encoding format of the data files.
     pub encoding: DataEncoding,
 
+    /// Transparent compression applied around the encoded data files.
+    pub compression: Compression,
+
     /// Strategy of the cache used.
     pub strategy: CacheStrategy,
 
     /// Number of subdirectories per level. (There are two levels.)
     pub subdirs_per_level: u32,
+
+    /// Time after which an entry is considered stale and will be evicted
+    /// on the next access, or `None` if entries should never expire.
+    pub ttl: Option<Duration>,
+
+    /// What to do when an on-disk cache was written by an incompatible
+    /// format version or config.
+    pub on_version_mismatch: MismatchPolicy,
 }
 
 #[derive(Clone, Debug)]
@@ -24,6 +37,36 @@ pub enum CacheStrategy {
     /// This is a good trade off keeping active values around and
     /// deleting old ones to make room for new ones.
     LRU,
+
+    /// First in, first out.
+    ///
+    /// Delete the value that was inserted longest ago. Unlike `LRU`,
+    /// reading a value through `get` never changes its position, so
+    /// eviction order only ever depends on insertion order.
+    FIFO,
+
+    /// Least frequently used.
+    ///
+    /// Delete the value that has been read the fewest times. Ties are
+    /// broken arbitrarily.
+    LFU,
+
+    /// Evict the largest entries first, to free up space as quickly as
+    /// possible. Reading a value has no effect on eviction order.
+    SizeWeighted,
+}
+
+impl CacheStrategy {
+    /// Short tag persisted alongside the cache metadata so a changed
+    /// strategy is detected as an incompatible cache on the next load.
+    pub(crate) fn tag(&self) -> &'static str {
+        match *self {
+            CacheStrategy::LRU => "lru",
+            CacheStrategy::FIFO => "fifo",
+            CacheStrategy::LFU => "lfu",
+            CacheStrategy::SizeWeighted => "size_weighted",
+        }
+    }
 }
 
 impl Default for CacheStrategy {
@@ -31,3 +74,20 @@ impl Default for CacheStrategy {
         CacheStrategy::LRU
     }
 }
+
+/// What to do when the on-disk cache format or config fingerprint doesn't
+/// match what is currently loaded.
+#[derive(Clone, Debug)]
+pub enum MismatchPolicy {
+    /// Discard the stale cache directory and start from an empty cache.
+    Clear,
+
+    /// Return a `CacheError` instead of discarding any data.
+    Fail,
+}
+
+impl Default for MismatchPolicy {
+    fn default() -> Self {
+        MismatchPolicy::Clear
+    }
+}
@@ -1,8 +1,12 @@
 use bincode;
+use flate2;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
 use serde_json;
 use std::io::{self, Read, Write};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
+use zstd;
 
 #[derive(Clone, Debug)]
 pub enum DataEncoding {
@@ -18,29 +22,77 @@ impl DataEncoding {
         }
     }
 
-    pub(crate) fn filename(&self, basename: &str) -> String {
-        format!("{}.{}", basename, self.extension())
+    /// The file extension to use, including the compression tag if any
+    /// (e.g. `bincode.zst`).
+    pub(crate) fn full_extension(&self, compression: &Compression) -> String {
+        match compression.tag() {
+            Some(tag) => format!("{}.{}", self.extension(), tag),
+            None => self.extension().to_string(),
+        }
+    }
+
+    pub(crate) fn filename(&self, basename: &str, compression: &Compression) -> String {
+        format!("{}.{}", basename, self.full_extension(compression))
     }
 
     pub(crate) fn serialize<T: Serialize, W: Write>(
         &self,
         writer: &mut W,
         value: &T,
+        compression: &Compression,
     ) -> Result<usize, SerializeError> {
-        let mut write_counter = WriteCounter::new(writer);
-        match *self {
-            DataEncoding::Bincode => bincode::serialize_into(&mut write_counter, value)
-                .map_err(|e| SerializeError::Bincode(e))?,
-            DataEncoding::Json => serde_json::to_writer(&mut write_counter, value)
-                .map_err(|e| SerializeError::Json(e))?,
-        };
-        Ok(write_counter.counter)
+        // The counter has to wrap the raw writer (rather than the other way
+        // around) so it counts the compressed bytes that actually hit disk.
+        let mut counter = WriteCounter::new(writer);
+        match *compression {
+            Compression::None => self.encode(&mut counter, value)?,
+            Compression::Zstd { level } => {
+                let mut encoder = zstd::stream::write::Encoder::new(&mut counter, level)
+                    .map_err(|e| SerializeError::WriteError(e))?;
+                self.encode(&mut encoder, value)?;
+                encoder
+                    .finish()
+                    .map_err(|e| SerializeError::WriteError(e))?;
+            }
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(&mut counter, flate2::Compression::default());
+                self.encode(&mut encoder, value)?;
+                encoder
+                    .finish()
+                    .map_err(|e| SerializeError::WriteError(e))?;
+            }
+        }
+        Ok(counter.counter)
     }
 
     pub(crate) fn deserialize<T: DeserializeOwned, R: Read>(
         &self,
         reader: R,
+        compression: &Compression,
     ) -> Result<T, DeserializeError> {
+        match *compression {
+            Compression::None => self.decode(reader),
+            Compression::Zstd { .. } => {
+                let decoder =
+                    zstd::stream::read::Decoder::new(reader).map_err(|e| DeserializeError::ReadError(e))?;
+                self.decode(decoder)
+            }
+            Compression::Gzip => self.decode(GzDecoder::new(reader)),
+        }
+    }
+
+    fn encode<T: Serialize, W: Write>(&self, writer: &mut W, value: &T) -> Result<(), SerializeError> {
+        match *self {
+            DataEncoding::Bincode => {
+                bincode::serialize_into(writer, value).map_err(|e| SerializeError::Bincode(e))
+            }
+            DataEncoding::Json => {
+                serde_json::to_writer(writer, value).map_err(|e| SerializeError::Json(e))
+            }
+        }
+    }
+
+    fn decode<T: DeserializeOwned, R: Read>(&self, reader: R) -> Result<T, DeserializeError> {
         match *self {
             DataEncoding::Bincode => {
                 bincode::deserialize_from(reader).map_err(|e| DeserializeError::Bincode(e))
@@ -52,6 +104,38 @@ impl DataEncoding {
     }
 }
 
+/// Transparent compression applied around the serialized bytes of a data
+/// file, independent of the chosen `DataEncoding`.
+#[derive(Clone, Debug)]
+pub enum Compression {
+    /// Store the serialized bytes as-is.
+    None,
+
+    /// Compress with zstd at the given level.
+    Zstd { level: i32 },
+
+    /// Compress with gzip (deflate).
+    Gzip,
+}
+
+impl Compression {
+    /// Short tag used in the on-disk file extension, e.g. `zst` for
+    /// `data_<digest>.bincode.zst`. `None` carries no tag.
+    fn tag(&self) -> Option<&'static str> {
+        match *self {
+            Compression::None => None,
+            Compression::Zstd { .. } => Some("zst"),
+            Compression::Gzip => Some("gz"),
+        }
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
 /// Write impl which provides a counter for the number of bytes written,
 /// even if the functions writing to it don't provide such information.
 struct WriteCounter<W> {
@@ -96,4 +180,7 @@ pub enum DeserializeError {
 
     #[fail(display = "Failed deserializing json: {:?}", _0)]
     Json(serde_json::Error),
+
+    #[fail(display = "Reading compressed data failed: {:?}", _0)]
+    ReadError(io::Error),
 }
@@ -0,0 +1,173 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+use super::CacheEntry;
+
+/// What should happen after an entry was read via `get`/`lookup`.
+pub(crate) struct AccessOutcome {
+    /// Whether the entry changed (and the on-disk metadata therefore
+    /// needs to be rewritten).
+    pub(crate) persist: bool,
+
+    /// Whether the entry's position in `order` should be refreshed to
+    /// the back.
+    pub(crate) reorder: bool,
+}
+
+/// Insertion/recency order of every live key, consulted by eviction
+/// policies that care about order (`LRU`, `FIFO`). `index` remains the
+/// source of truth for entry contents; this only tracks ordering.
+///
+/// `addressable_queue`'s `Queue` was tried here first, but its public API
+/// has no way to peek the oldest key without removing it, which `LRU`/
+/// `FIFO` need from behind a shared reference. A plain `VecDeque` covers
+/// that at the cost of an O(n) scan on `remove_key`, which is acceptable
+/// since cache sizes here are bounded by `max_bytes`, not key count.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct OrderQueue<K> {
+    keys: VecDeque<K>,
+}
+
+impl<K> OrderQueue<K>
+where
+    K: Clone + Eq,
+{
+    pub(crate) fn new() -> Self {
+        OrderQueue {
+            keys: VecDeque::new(),
+        }
+    }
+
+    /// Appends `key` to the back, i.e. the most-recently-inserted end.
+    pub(crate) fn insert(&mut self, key: K) {
+        self.keys.push_back(key);
+    }
+
+    /// Removes `key` from wherever it currently sits. A no-op if it isn't
+    /// tracked.
+    pub(crate) fn remove_key(&mut self, key: &K) {
+        if let Some(pos) = self.keys.iter().position(|k| k == key) {
+            self.keys.remove(pos);
+        }
+    }
+
+    /// The oldest tracked key, i.e. the one `LRU`/`FIFO` would evict next.
+    pub(crate) fn oldest(&self) -> Option<&K> {
+        self.keys.front()
+    }
+}
+
+/// Decides how `SimpleCache` picks an entry to evict and how it reacts to
+/// an entry being accessed, backing the choice of `CacheStrategy`.
+pub(crate) trait EvictionPolicy<K>
+where
+    K: Clone + Eq + Hash,
+{
+    /// Called right after a brand new entry has been inserted, to let the
+    /// policy initialize whatever bookkeeping it keeps inside the entry.
+    fn on_insert(&self, entry: &mut CacheEntry);
+
+    /// Called when an entry is read via `get`/`lookup`, with the chance to
+    /// mutate its bookkeeping in place.
+    fn on_access(&self, entry: &mut CacheEntry) -> AccessOutcome;
+
+    /// Picks the key of the entry that should be evicted next, if any.
+    fn next_victim(&self, order: &OrderQueue<K>, index: &HashMap<K, CacheEntry>) -> Option<K>;
+}
+
+/// Least recently used: evict the entry that hasn't been read in the
+/// longest time. Reading an entry moves it to the back of `order`.
+pub(crate) struct Lru;
+
+impl<K> EvictionPolicy<K> for Lru
+where
+    K: Clone + Eq + Hash,
+{
+    fn on_insert(&self, _entry: &mut CacheEntry) {}
+
+    fn on_access(&self, _entry: &mut CacheEntry) -> AccessOutcome {
+        AccessOutcome {
+            persist: true,
+            reorder: true,
+        }
+    }
+
+    fn next_victim(&self, order: &OrderQueue<K>, _index: &HashMap<K, CacheEntry>) -> Option<K> {
+        order.oldest().cloned()
+    }
+}
+
+/// First in, first out: evict the entry that was inserted longest ago.
+/// Reading an entry never changes its position.
+pub(crate) struct Fifo;
+
+impl<K> EvictionPolicy<K> for Fifo
+where
+    K: Clone + Eq + Hash,
+{
+    fn on_insert(&self, _entry: &mut CacheEntry) {}
+
+    fn on_access(&self, _entry: &mut CacheEntry) -> AccessOutcome {
+        AccessOutcome {
+            persist: false,
+            reorder: false,
+        }
+    }
+
+    fn next_victim(&self, order: &OrderQueue<K>, _index: &HashMap<K, CacheEntry>) -> Option<K> {
+        order.oldest().cloned()
+    }
+}
+
+/// Least frequently used: evict the entry that has been read the fewest
+/// times. The write that creates an entry counts as its first use.
+pub(crate) struct Lfu;
+
+impl<K> EvictionPolicy<K> for Lfu
+where
+    K: Clone + Eq + Hash,
+{
+    fn on_insert(&self, entry: &mut CacheEntry) {
+        entry.access_count = 1;
+    }
+
+    fn on_access(&self, entry: &mut CacheEntry) -> AccessOutcome {
+        entry.access_count += 1;
+        AccessOutcome {
+            persist: true,
+            reorder: false,
+        }
+    }
+
+    fn next_victim(&self, _order: &OrderQueue<K>, index: &HashMap<K, CacheEntry>) -> Option<K> {
+        index
+            .iter()
+            .min_by_key(|&(_, entry)| entry.access_count)
+            .map(|(key, _)| key.clone())
+    }
+}
+
+/// Evict the largest entries first, to free up space as quickly as
+/// possible. Reading an entry has no effect on eviction order.
+pub(crate) struct SizeWeighted;
+
+impl<K> EvictionPolicy<K> for SizeWeighted
+where
+    K: Clone + Eq + Hash,
+{
+    fn on_insert(&self, _entry: &mut CacheEntry) {}
+
+    fn on_access(&self, _entry: &mut CacheEntry) -> AccessOutcome {
+        AccessOutcome {
+            persist: false,
+            reorder: false,
+        }
+    }
+
+    fn next_victim(&self, _order: &OrderQueue<K>, index: &HashMap<K, CacheEntry>) -> Option<K> {
+        index
+            .iter()
+            .max_by_key(|&(_, entry)| entry.size())
+            .map(|(key, _)| key.clone())
+    }
+}
@@ -1,38 +1,175 @@
-extern crate addressable_queue;
 extern crate bincode;
 #[macro_use]
 extern crate failure;
+extern crate flate2;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate sha2;
+extern crate zstd;
 
-use addressable_queue::fifo::Queue;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
 use std::fs::{self, File};
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::marker::PhantomData;
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+mod encoding;
+mod eviction;
 pub mod config;
-use self::config::CacheConfig;
+use self::config::{CacheConfig, CacheStrategy, MismatchPolicy};
+use self::eviction::{EvictionPolicy, OrderQueue};
+
+/// Version of the on-disk cache format. Bump this whenever a change to
+/// `Metadata` or `CacheEntry` would make existing cache directories
+/// unreadable or misread, so they get invalidated instead.
+const CURRENT_VERSION: u32 = 4;
 
 #[derive(Serialize, Deserialize)]
 struct Metadata<K>
 where
     K: Clone + Eq + Hash,
 {
-    current_size: u64,
-    counter: u64,
-    entries: Queue<K, CacheEntry>,
+    version: u32,
+    encoding_tag: String,
+    strategy_tag: String,
+
+    /// Sum of every entry's `size`, i.e. what each key is charged for LRU
+    /// fairness, double-counting keys that share a deduplicated payload.
+    logical_size: u64,
+
+    /// Bytes actually occupied on disk, counting each distinct payload
+    /// once. `cleanup` evicts against this, not `logical_size`.
+    physical_size: u64,
+
+    /// Number of live entries referencing each content digest.
+    refcounts: HashMap<String, u32>,
+
+    /// Insertion/recency order of every live key, consulted by eviction
+    /// policies that care about order (`LRU`, `FIFO`). Carries no value of
+    /// its own; `index` is the source of truth for entry contents.
+    order: OrderQueue<K>,
+
+    /// Every live entry, keyed for O(1) lookup. `lookup`/`get` read
+    /// through here directly instead of scanning `order`.
+    index: HashMap<K, CacheEntry>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl<K> Metadata<K>
+where
+    K: Clone + Eq + Hash,
+{
+    fn fresh(config: &CacheConfig) -> Self {
+        Metadata {
+            version: CURRENT_VERSION,
+            encoding_tag: config.encoding.extension().to_string(),
+            strategy_tag: config.strategy.tag().to_string(),
+            logical_size: 0,
+            physical_size: 0,
+            refcounts: HashMap::new(),
+            order: OrderQueue::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    /// Whether this metadata was produced by the same cache format version
+    /// and config fingerprint that `config` describes.
+    fn is_compatible_with(&self, config: &CacheConfig) -> bool {
+        self.version == CURRENT_VERSION && self.encoding_tag == config.encoding.extension()
+            && self.strategy_tag == config.strategy.tag()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct CacheEntry {
-    size: u64,
-    id: u64,
+    /// Unix timestamp (seconds) at which this entry was written.
+    ///
+    /// `#[serde(default)]` only helps here with a self-describing format
+    /// like `DataEncoding::Json`; `Bincode` has no field names to match
+    /// against and would fail to deserialize an entry missing this field
+    /// rather than default it. In practice this is moot either way: the
+    /// metadata of a cache directory written before this field existed
+    /// either fails to deserialize or carries an older `CURRENT_VERSION`
+    /// that `Metadata::is_compatible_with` rejects, and both cases are
+    /// handled by `on_version_mismatch`.
+    #[serde(default)]
+    created_at: u64,
+
+    /// Use count of this entry. Only maintained and consulted by
+    /// `CacheStrategy::LFU`, where it starts at `1` for the write that
+    /// created the entry and grows by one per read via `get`/`lookup`;
+    /// otherwise left at `0`.
+    #[serde(default)]
+    access_count: u64,
+
+    kind: EntryKind,
+}
+
+impl CacheEntry {
+    /// The number of bytes this entry charges towards `logical_size`,
+    /// i.e. the size of its (possibly shared) data file, or `0` for a
+    /// negative entry.
+    fn size(&self) -> u64 {
+        match self.kind {
+            EntryKind::Value { size, .. } => size,
+            EntryKind::Negative { .. } => 0,
+        }
+    }
+}
+
+/// What a `CacheEntry` actually holds: either a real value on disk, or a
+/// record that the key is known to have no value.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum EntryKind {
+    /// `digest` is the hex content hash of the serialized (and possibly
+    /// compressed) value, shared by every key whose value is
+    /// byte-identical.
+    Value { size: u64, digest: String },
+
+    /// A negative cache entry: occupies no data file and no disk space,
+    /// but still participates in the eviction queue and has its own
+    /// expiry.
+    Negative { expires_at: u64 },
+}
+
+/// Hex-encoded SHA-256 digest of `bytes`, used as the content-addressed
+/// data-file id so byte-identical payloads are stored only once.
+fn content_digest(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The outcome of a `SimpleCache::lookup`, distinguishing "cached as
+/// absent" from "never seen".
+pub enum Lookup<V> {
+    Hit(V),
+    NegativeHit,
+    Miss,
+}
+
+/// Returns the current time as a unix timestamp in seconds.
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is before the unix epoch")
+        .as_secs()
+}
+
+/// The sibling path a file is first written to before being atomically
+/// renamed into place, e.g. `cache_data.json.tmp` for `cache_data.json`.
+fn tmp_path(path: &PathBuf) -> PathBuf {
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
 }
 
 pub struct SimpleCache<K, V>
@@ -60,19 +197,28 @@ where
             fs::create_dir_all(&data_dir).map_err(|e| CacheError::CreateDir(e))?;
         }
 
-        let metadata_file = data_dir.join(config.encoding.filename("cache_data"));
-        let metadata = if metadata_file.exists() {
-            let file = File::open(&metadata_file).map_err(|e| CacheError::ReadMetadata(e))?;
+        let metadata_file = data_dir.join(
             config
                 .encoding
-                .deserialize(file)
-                .map_err(|e| CacheError::DeserializeMetadata(e))?
-        } else {
-            Metadata {
-                current_size: 0,
-                entries: Queue::new(),
-                counter: 0,
+                .filename("cache_data", &config.compression),
+        );
+        let metadata = if metadata_file.exists() {
+            let file = File::open(&metadata_file).map_err(|e| CacheError::ReadMetadata(e))?;
+            let loaded: Result<Metadata<K>, _> =
+                config.encoding.deserialize(file, &config.compression);
+
+            let compatible = match &loaded {
+                Ok(m) => m.is_compatible_with(&config),
+                Err(_) => false,
+            };
+
+            if compatible {
+                loaded.unwrap()
+            } else {
+                Self::handle_stale_metadata(&data_dir, &config, loaded.err())?
             }
+        } else {
+            Metadata::fresh(&config)
         };
 
         Ok(SimpleCache {
@@ -84,62 +230,147 @@ where
         })
     }
 
+    /// Handles a cache directory whose metadata is either unreadable or
+    /// stamped with an incompatible version/config fingerprint, according
+    /// to `config.on_version_mismatch`.
+    fn handle_stale_metadata(
+        data_dir: &PathBuf,
+        config: &CacheConfig,
+        error: Option<config::DeserializeError>,
+    ) -> Result<Metadata<K>, CacheError> {
+        match config.on_version_mismatch {
+            MismatchPolicy::Fail => Err(match error {
+                Some(e) => CacheError::DeserializeMetadata(e),
+                None => CacheError::VersionMismatch,
+            }),
+            MismatchPolicy::Clear => {
+                fs::remove_dir_all(data_dir).map_err(|e| CacheError::ClearCache(e))?;
+                fs::create_dir_all(data_dir).map_err(|e| CacheError::CreateDir(e))?;
+                Ok(Metadata::fresh(config))
+            }
+        }
+    }
+
     /// Try getting a value from the cache.
     ///
     /// Unless there is an error, this will either return `Ok(Some(value))` if a value was found,
-    /// or `Ok(None)` if no value for the key exists in the cache.
+    /// or `Ok(None)` if no value for the key exists in the cache, including if it is
+    /// cached as a [`put_negative`](SimpleCache::put_negative) entry. Use
+    /// [`lookup`](SimpleCache::lookup) to tell those two cases apart.
     pub fn get(&mut self, key: &K) -> Result<Option<V>, CacheError> {
-        if let Some(item) = self.metadata.entries.remove_key(key) {
-            // Read the value from the disk.
-            let file_path = self.data_file_path(item.id)?;
-            let file = File::open(file_path).map_err(|e| CacheError::ReadCacheFile(e))?;
-            let value = self.config
-                .encoding
-                .deserialize(file)
-                .map_err(|e| CacheError::DeserializeValue(e))?;
+        match self.lookup(key)? {
+            Lookup::Hit(value) => Ok(Some(value)),
+            Lookup::NegativeHit | Lookup::Miss => Ok(None),
+        }
+    }
+
+    /// Try getting a value from the cache, distinguishing a genuine miss
+    /// from a key that was explicitly cached as absent via
+    /// [`put_negative`](SimpleCache::put_negative).
+    pub fn lookup(&mut self, key: &K) -> Result<Lookup<V>, CacheError> {
+        // O(1) peek through the index; `order` is never consulted for a
+        // plain read.
+        let mut item = match self.metadata.index.get(key) {
+            Some(entry) => entry.clone(),
+            None => return Ok(Lookup::Miss),
+        };
 
-            // Insert the item again at the end of the queue.
-            self.metadata.entries.insert(key.clone(), item);
+        if self.is_expired(&item) {
+            // The entry is stale: drop its data file (if any) and do not
+            // reinsert it into either structure.
+            self.metadata.order.remove_key(key);
+            self.metadata.index.remove(key);
+            self.evict_entry(&item)?;
             self.write_metadata()?;
-            Ok(Some(value))
-        } else {
-            // The cache does not store a relevant entry.
-            Ok(None)
+            return Ok(Lookup::Miss);
+        }
+
+        let outcome = self.policy().on_access(&mut item);
+        if outcome.reorder {
+            // Move to the back of the recency order (`LRU` only).
+            self.metadata.order.remove_key(key);
+            self.metadata.order.insert(key.clone());
+        }
+        if outcome.persist {
+            // Persist whatever bookkeeping `on_access` just mutated.
+            self.metadata.index.insert(key.clone(), item.clone());
+            self.write_metadata()?;
+        }
+
+        match item.kind {
+            EntryKind::Negative { .. } => Ok(Lookup::NegativeHit),
+            EntryKind::Value { ref digest, .. } => {
+                let file_path = self.data_file_path(digest)?;
+                let file = File::open(file_path).map_err(|e| CacheError::ReadCacheFile(e))?;
+                let value = self.config
+                    .encoding
+                    .deserialize(file, &self.config.compression)
+                    .map_err(|e| CacheError::DeserializeValue(e))?;
+                Ok(Lookup::Hit(value))
+            }
+        }
+    }
+
+    /// The eviction policy backing `self.config.strategy`.
+    fn policy(&self) -> Box<dyn EvictionPolicy<K>> {
+        match self.config.strategy {
+            CacheStrategy::LRU => Box::new(eviction::Lru),
+            CacheStrategy::FIFO => Box::new(eviction::Fifo),
+            CacheStrategy::LFU => Box::new(eviction::Lfu),
+            CacheStrategy::SizeWeighted => Box::new(eviction::SizeWeighted),
         }
     }
 
     /// Insert a value into the cache.
     ///
     /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// If another key already holds a byte-identical serialized value, the
+    /// payload is stored once on disk and shared between both keys via
+    /// reference counting.
     pub fn put(&mut self, key: &K, value: &V) -> Result<(), CacheError> {
-        let entry_id = if let Some(entry) = self.metadata.entries.remove_key(key) {
-            // Reuse the same file.
-            // Note that later it will be added again to data.entries.
-            entry.id
-        } else {
-            // Create a new entry.
-            let entry_id = self.metadata.counter;
-            self.metadata.counter += 1;
-            entry_id
-        };
-
-        // Write the file.
-        let file_path = self.data_file_path(entry_id)?;
-        let mut file = File::create(&file_path).map_err(|e| CacheError::CreateFile(e, file_path))?;
+        // Serialize (and compress) into memory first, both to compute a
+        // content digest for deduplication and to know the final size
+        // before touching disk.
+        let mut buffer = Vec::new();
         let bytes = self.config
             .encoding
-            .serialize(&mut file, value)
+            .serialize(&mut buffer, value, &self.config.compression)
             .map_err(|e| CacheError::SerializeValue(e))? as u64;
+        let digest = content_digest(&buffer);
+
+        let file_path = self.data_file_path(&digest)?;
+        if !self.metadata.refcounts.contains_key(&digest) {
+            // First key to reference this exact payload: write it once,
+            // atomically so a crash mid-write never leaves a corrupt file.
+            let tmp_path = tmp_path(&file_path);
+            fs::write(&tmp_path, &buffer).map_err(|e| CacheError::CreateFile(e, tmp_path.clone()))?;
+            fs::rename(&tmp_path, &file_path).map_err(|e| CacheError::RenameFile(e, file_path))?;
+            self.metadata.physical_size += bytes;
+        }
+        *self.metadata.refcounts.entry(digest.clone()).or_insert(0) += 1;
+
+        // Only now that the new payload is safely on disk do we release
+        // whatever this key pointed at before: if the write above had
+        // failed, the previous value must still be intact.
+        if let Some(old) = self.metadata.index.remove(key) {
+            self.metadata.order.remove_key(key);
+            self.evict_entry(&old)?;
+        }
 
         // Put the entry into the data struct.
-        self.metadata.entries.insert(
-            key.clone(),
-            CacheEntry {
+        let mut entry = CacheEntry {
+            created_at: now_unix(),
+            access_count: 0,
+            kind: EntryKind::Value {
                 size: bytes,
-                id: entry_id,
+                digest,
             },
-        );
-        self.metadata.current_size += bytes;
+        };
+        self.policy().on_insert(&mut entry);
+        self.metadata.order.insert(key.clone());
+        self.metadata.index.insert(key.clone(), entry);
+        self.metadata.logical_size += bytes;
 
         // Cleanup entries if needed.
         self.cleanup()?;
@@ -150,22 +381,59 @@ where
         Ok(())
     }
 
-    fn write_metadata(&self) -> Result<(), CacheError> {
-        let mut file = File::create(&self.metadata_file)
-            .map_err(|e| CacheError::CreateFile(e, self.metadata_file.clone()))?;
+    /// Record that `key` is known to have no value, or that producing one
+    /// failed, so callers can avoid repeatedly retrying an expensive
+    /// lookup. The entry itself expires after `ttl`, independent of the
+    /// cache-wide `CacheConfig::ttl`.
+    pub fn put_negative(&mut self, key: &K, ttl: Duration) -> Result<(), CacheError> {
+        // Drop any previous entry for this key, freeing its data file if
+        // it held a real value.
+        if let Some(old) = self.metadata.index.remove(key) {
+            self.metadata.order.remove_key(key);
+            self.evict_entry(&old)?;
+        }
 
-        self.config
-            .encoding
-            .serialize(&mut file, &self.metadata)
-            .map_err(|e| CacheError::SerializeMetadata(e))?;
+        let mut entry = CacheEntry {
+            created_at: now_unix(),
+            access_count: 0,
+            kind: EntryKind::Negative {
+                expires_at: now_unix() + ttl.as_secs(),
+            },
+        };
+        self.policy().on_insert(&mut entry);
+        self.metadata.order.insert(key.clone());
+        self.metadata.index.insert(key.clone(), entry);
+
+        self.write_metadata()?;
         Ok(())
     }
 
-    fn data_file_path(&self, entry_id: u64) -> Result<PathBuf, CacheError> {
-        // Determine file subdirectory.
+    fn write_metadata(&self) -> Result<(), CacheError> {
+        let tmp_path = tmp_path(&self.metadata_file);
+        {
+            let mut file = File::create(&tmp_path)
+                .map_err(|e| CacheError::CreateFile(e, tmp_path.clone()))?;
+            self.config
+                .encoding
+                .serialize(&mut file, &self.metadata, &self.config.compression)
+                .map_err(|e| CacheError::SerializeMetadata(e))?;
+        }
+        fs::rename(&tmp_path, &self.metadata_file)
+            .map_err(|e| CacheError::RenameFile(e, self.metadata_file.clone()))?;
+        Ok(())
+    }
+
+    /// The path of the (possibly shared) data file holding the payload
+    /// with the given content digest.
+    fn data_file_path(&self, digest: &str) -> Result<PathBuf, CacheError> {
+        // Determine file subdirectory, spreading digests across buckets
+        // the same way entry ids used to be spread.
+        let mut hasher = DefaultHasher::new();
+        digest.hash(&mut hasher);
+        let h = hasher.finish();
         let s = self.config.subdirs_per_level as u64;
-        let subdir_1 = entry_id % s;
-        let subdir_2 = (entry_id / s) % s;
+        let subdir_1 = h % s;
+        let subdir_2 = (h / s) % s;
 
         // Assert the directory exists.
         let dir = self.data_dir.join(format!("{}/{}", subdir_1, subdir_2));
@@ -174,8 +442,8 @@ where
         // Determine file path.
         let path = Ok(dir.join(format!(
             "data_{}.{}",
-            entry_id,
-            self.config.encoding.extension()
+            digest,
+            self.config.encoding.full_extension(&self.config.compression)
         )));
         path
     }
@@ -183,14 +451,80 @@ where
     /// Deletes as many cache entries as needed until the maximum storage is
     /// free again.
     fn cleanup(&mut self) -> Result<(), CacheError> {
-        while self.metadata.current_size > self.config.max_bytes {
-            let (_, entry) = self.metadata.entries.remove_head().unwrap();
-            self.metadata.current_size -= entry.size;
-            let path = self.data_file_path(entry.id)?;
-            fs::remove_file(&path).map_err(|e| CacheError::RemoveFile(e, path))?;
+        while self.metadata.physical_size > self.config.max_bytes {
+            let victim = self.policy()
+                .next_victim(&self.metadata.order, &self.metadata.index)
+                .ok_or(CacheError::CleanupQueueEmpty)?;
+            self.metadata.order.remove_key(&victim);
+            let entry = self.metadata
+                .index
+                .remove(&victim)
+                .ok_or(CacheError::CleanupQueueEmpty)?;
+            self.evict_entry(&entry)?;
+        }
+        Ok(())
+    }
+
+    /// Whether `entry` is past its time-to-live, given the current config.
+    fn is_expired(&self, entry: &CacheEntry) -> bool {
+        match entry.kind {
+            EntryKind::Value { .. } => match self.config.ttl {
+                Some(ttl) => now_unix().saturating_sub(entry.created_at) >= ttl.as_secs(),
+                None => false,
+            },
+            EntryKind::Negative { expires_at } => now_unix() >= expires_at,
+        }
+    }
+
+    /// Releases `entry`'s reference to its data file (if it has one),
+    /// updating `logical_size` and, once the last reference to a shared
+    /// payload is gone, deleting the file and updating `physical_size`.
+    /// The caller is responsible for removing `entry` from the queue.
+    fn evict_entry(&mut self, entry: &CacheEntry) -> Result<(), CacheError> {
+        if let EntryKind::Value { ref digest, size } = entry.kind {
+            self.metadata.logical_size -= size;
+
+            let remaining = {
+                let refcount = self.metadata
+                    .refcounts
+                    .get_mut(digest)
+                    .expect("refcount missing for a live cache entry");
+                *refcount -= 1;
+                *refcount
+            };
+
+            if remaining == 0 {
+                self.metadata.refcounts.remove(digest);
+                let path = self.data_file_path(digest)?;
+                fs::remove_file(&path).map_err(|e| CacheError::RemoveFile(e, path))?;
+                self.metadata.physical_size -= size;
+            }
         }
         Ok(())
     }
+
+    /// Removes every expired entry from the cache in one pass, returning the
+    /// number of entries that were evicted.
+    pub fn purge_expired(&mut self) -> Result<usize, CacheError> {
+        let stale_keys: Vec<K> = self.metadata
+            .index
+            .iter()
+            .filter(|&(_, entry)| self.is_expired(entry))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &stale_keys {
+            self.metadata.order.remove_key(key);
+            let entry = self.metadata.index.remove(key).unwrap();
+            self.evict_entry(&entry)?;
+        }
+
+        if !stale_keys.is_empty() {
+            self.write_metadata()?;
+        }
+
+        Ok(stale_keys.len())
+    }
 }
 
 /// Various errors that can occur when operating a cache.
@@ -225,4 +559,16 @@ pub enum CacheError {
 
     #[fail(display = "Deleting file failed: {:?}, filename = '{:?}'", _0, _1)]
     RemoveFile(io::Error, PathBuf),
+
+    #[fail(display = "Cache metadata version or config fingerprint does not match the current one")]
+    VersionMismatch,
+
+    #[fail(display = "Clearing stale cache directory failed: {:?}", _0)]
+    ClearCache(io::Error),
+
+    #[fail(display = "Renaming temporary file into place failed: {:?}, filename = '{:?}'", _0, _1)]
+    RenameFile(io::Error, PathBuf),
+
+    #[fail(display = "Cannot evict an entry: the cache queue is empty, but max_bytes is still exceeded (is max_bytes smaller than a single entry?)")]
+    CleanupQueueEmpty,
 }
@@ -1,8 +1,9 @@
 extern crate simple_disk_cache;
 extern crate tempdir;
 
-use simple_disk_cache::SimpleCache;
-use simple_disk_cache::config::{CacheConfig, CacheStrategy, DataEncoding};
+use simple_disk_cache::{CacheError, Lookup, SimpleCache};
+use simple_disk_cache::config::{CacheConfig, CacheStrategy, Compression, DataEncoding, MismatchPolicy};
+use std::time::Duration;
 use tempdir::TempDir;
 
 /// For testing purposes `u32` and `u64` are used because then
@@ -22,6 +23,9 @@ fn basic_usage(encoding: DataEncoding) {
         encoding,
         strategy: CacheStrategy::LRU,
         subdirs_per_level: 3,
+        ttl: None,
+        compression: Compression::None,
+        on_version_mismatch: MismatchPolicy::Clear,
     };
     let mut cache =
         TestCache::initialize(tempdir.as_ref(), config).expect("failed initializing cache.");
@@ -59,6 +63,9 @@ fn restore_cache(encoding: DataEncoding) {
         encoding,
         strategy: CacheStrategy::LRU,
         subdirs_per_level: 3,
+        ttl: None,
+        compression: Compression::None,
+        on_version_mismatch: MismatchPolicy::Clear,
     };
     let config2 = config1.clone();
     let mut cache =
@@ -91,3 +98,377 @@ fn restore_cache_json() {
 fn restore_cache_bincode() {
     restore_cache(DataEncoding::Bincode)
 }
+
+#[test]
+fn put_negative_is_a_negative_hit_until_its_own_ttl_expires() {
+    let tempdir = get_tempdir("put_negative");
+    let mut cache =
+        TestCache::initialize(tempdir.as_ref(), config_with_strategy(CacheStrategy::LRU))
+            .expect("failed initializing cache.");
+
+    // Never seen: a plain miss.
+    match cache.lookup(&1).expect("failed reading from cache.") {
+        Lookup::Miss => {}
+        _ => panic!("expected a plain Miss for a key that was never written."),
+    }
+
+    cache
+        .put_negative(&1, Duration::from_secs(60))
+        .expect("failed writing a negative cache entry.");
+    match cache.lookup(&1).expect("failed reading from cache.") {
+        Lookup::NegativeHit => {}
+        _ => panic!("expected NegativeHit for a key cached as absent."),
+    }
+    // get() collapses NegativeHit and Miss, both surfacing as None.
+    assert_eq!(cache.get(&1).expect("failed reading from cache."), None);
+
+    // A negative entry's own ttl is independent of the cache-wide one:
+    // an expired negative entry reads back as a plain Miss.
+    cache
+        .put_negative(&2, Duration::from_secs(0))
+        .expect("failed writing a negative cache entry.");
+    match cache.lookup(&2).expect("failed reading from cache.") {
+        Lookup::Miss => {}
+        _ => panic!("expected the expired negative entry to read back as a Miss."),
+    }
+}
+
+#[test]
+fn put_negative_overwrites_a_previous_value() {
+    let tempdir = get_tempdir("put_negative_overwrite");
+    let mut cache =
+        TestCache::initialize(tempdir.as_ref(), config_with_strategy(CacheStrategy::LRU))
+            .expect("failed initializing cache.");
+
+    cache.put(&1, &2).expect("failed writing to cache.");
+    cache
+        .put_negative(&1, Duration::from_secs(60))
+        .expect("failed writing a negative cache entry.");
+    assert_eq!(cache.get(&1).expect("failed reading from cache."), None);
+    match cache.lookup(&1).expect("failed reading from cache.") {
+        Lookup::NegativeHit => {}
+        _ => panic!("expected NegativeHit after put_negative overwrote a real value."),
+    }
+}
+
+/// Recursively collects every file name under `dir`, to check that no
+/// `.tmp` sibling was left behind by an atomic rename-into-place write.
+fn all_file_names(dir: &std::path::Path) -> Vec<String> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir).expect("failed reading directory.") {
+        let entry = entry.expect("failed reading directory entry.");
+        let path = entry.path();
+        if path.is_dir() {
+            names.extend(all_file_names(&path));
+        } else {
+            names.push(entry.file_name().into_string().expect("non-utf8 filename"));
+        }
+    }
+    names
+}
+
+fn config_with_strategy_and_max_bytes(strategy: CacheStrategy, max_bytes: u64) -> CacheConfig {
+    let mut config = config_with_strategy(strategy);
+    config.max_bytes = max_bytes;
+    config
+}
+
+#[test]
+fn eviction_lru_evicts_the_least_recently_used() {
+    let tempdir = get_tempdir("eviction_lru");
+    let mut cache = TestCache::initialize(
+        tempdir.as_ref(),
+        config_with_strategy_and_max_bytes(CacheStrategy::LRU, 3),
+    ).expect("failed initializing cache.");
+
+    // Single-digit values serialize to exactly one byte each in JSON.
+    cache.put(&0, &0).expect("failed writing to cache.");
+    cache.put(&1, &1).expect("failed writing to cache.");
+    cache.put(&2, &2).expect("failed writing to cache.");
+
+    // Touching key 0 moves it to the back of the recency order, so key 1
+    // becomes the least recently used.
+    cache.get(&0).expect("failed reading from cache.");
+    cache.put(&3, &3).expect("failed writing to cache.");
+
+    assert_eq!(cache.get(&1).expect("failed reading from cache."), None);
+    assert_eq!(cache.get(&0).expect("failed reading from cache."), Some(0));
+    assert_eq!(cache.get(&2).expect("failed reading from cache."), Some(2));
+    assert_eq!(cache.get(&3).expect("failed reading from cache."), Some(3));
+}
+
+#[test]
+fn eviction_fifo_evicts_the_oldest_insertion_regardless_of_reads() {
+    let tempdir = get_tempdir("eviction_fifo");
+    let mut cache = TestCache::initialize(
+        tempdir.as_ref(),
+        config_with_strategy_and_max_bytes(CacheStrategy::FIFO, 3),
+    ).expect("failed initializing cache.");
+
+    cache.put(&0, &0).expect("failed writing to cache.");
+    cache.put(&1, &1).expect("failed writing to cache.");
+    cache.put(&2, &2).expect("failed writing to cache.");
+
+    // Unlike LRU, reading key 0 doesn't protect it from eviction.
+    cache.get(&0).expect("failed reading from cache.");
+    cache.put(&3, &3).expect("failed writing to cache.");
+    cache.put(&4, &4).expect("failed writing to cache.");
+
+    assert_eq!(cache.get(&0).expect("failed reading from cache."), None);
+    assert_eq!(cache.get(&1).expect("failed reading from cache."), None);
+    assert_eq!(cache.get(&2).expect("failed reading from cache."), Some(2));
+    assert_eq!(cache.get(&3).expect("failed reading from cache."), Some(3));
+    assert_eq!(cache.get(&4).expect("failed reading from cache."), Some(4));
+}
+
+#[test]
+fn eviction_lfu_evicts_the_least_frequently_used() {
+    let tempdir = get_tempdir("eviction_lfu");
+    let mut cache = TestCache::initialize(
+        tempdir.as_ref(),
+        config_with_strategy_and_max_bytes(CacheStrategy::LFU, 3),
+    ).expect("failed initializing cache.");
+
+    cache.put(&0, &0).expect("failed writing to cache.");
+    cache.put(&1, &1).expect("failed writing to cache.");
+    cache.put(&2, &2).expect("failed writing to cache.");
+
+    // Read key 0 twice and key 2 once, so both are strictly more frequently
+    // used (access_count 3 and 2) than key 1, which sits at the initial
+    // access_count of 1 set by `on_insert`.
+    cache.get(&0).expect("failed reading from cache.");
+    cache.get(&0).expect("failed reading from cache.");
+    cache.get(&2).expect("failed reading from cache.");
+    cache.put(&3, &3).expect("failed writing to cache.");
+
+    // Key 3 also starts at access_count 1, tying it with key 1 for least
+    // frequently used; per `CacheStrategy::LFU`'s documented semantics
+    // ("ties are broken arbitrarily") exactly one of the two is evicted,
+    // while the more frequently used keys are never touched.
+    let key1_survived = cache.get(&1).expect("failed reading from cache.").is_some();
+    let key3_survived = cache.get(&3).expect("failed reading from cache.").is_some();
+    assert_ne!(key1_survived, key3_survived, "exactly one of the tied keys should survive");
+    assert_eq!(cache.get(&0).expect("failed reading from cache."), Some(0));
+    assert_eq!(cache.get(&2).expect("failed reading from cache."), Some(2));
+}
+
+#[test]
+fn eviction_size_weighted_evicts_the_largest_entries_first() {
+    let tempdir = get_tempdir("eviction_size_weighted");
+    let mut cache = TestCache::initialize(
+        tempdir.as_ref(),
+        config_with_strategy_and_max_bytes(CacheStrategy::SizeWeighted, 9),
+    ).expect("failed initializing cache.");
+
+    cache.put(&0, &0).expect("failed writing to cache."); // 1 byte
+    cache.put(&1, &100).expect("failed writing to cache."); // 3 bytes
+    cache.put(&2, &100000).expect("failed writing to cache."); // 6 bytes, pushes total past max_bytes
+
+    assert_eq!(cache.get(&2).expect("failed reading from cache."), None);
+    assert_eq!(cache.get(&0).expect("failed reading from cache."), Some(0));
+    assert_eq!(cache.get(&1).expect("failed reading from cache."), Some(100));
+}
+
+fn data_file_count(dir: &std::path::Path) -> usize {
+    all_file_names(dir)
+        .into_iter()
+        .filter(|name| name.starts_with("data_"))
+        .count()
+}
+
+#[test]
+fn identical_values_share_a_single_data_file_until_the_last_reference_is_gone() {
+    let tempdir = get_tempdir("dedup");
+    let mut cache =
+        TestCache::initialize(tempdir.as_ref(), config_with_strategy(CacheStrategy::LRU))
+            .expect("failed initializing cache.");
+
+    // Two keys, byte-identical serialized value: stored once.
+    cache.put(&1, &42).expect("failed writing to cache.");
+    cache.put(&2, &42).expect("failed writing to cache.");
+    assert_eq!(data_file_count(tempdir.as_ref()), 1);
+    assert_eq!(cache.get(&1).expect("failed reading from cache."), Some(42));
+    assert_eq!(cache.get(&2).expect("failed reading from cache."), Some(42));
+
+    // Overwriting one key's value releases its reference; the other key
+    // still shares the (still live) file.
+    cache.put(&1, &7).expect("failed writing to cache.");
+    assert_eq!(data_file_count(tempdir.as_ref()), 2);
+    assert_eq!(cache.get(&2).expect("failed reading from cache."), Some(42));
+
+    // Once the last key referencing the shared payload is also
+    // overwritten, its data file is actually deleted.
+    cache.put(&2, &7).expect("failed writing to cache.");
+    assert_eq!(data_file_count(tempdir.as_ref()), 1);
+}
+
+#[test]
+fn no_tmp_files_remain_after_normal_operation() {
+    let tempdir = get_tempdir("no_tmp_leftovers");
+    let mut cache =
+        TestCache::initialize(tempdir.as_ref(), config_with_strategy(CacheStrategy::LRU))
+            .expect("failed initializing cache.");
+
+    for k in 0..10 {
+        cache.put(&k, &(k as u64)).expect("failed writing to cache.");
+    }
+    for k in 0..10 {
+        cache.get(&k).expect("failed reading from cache.");
+    }
+    // Overwrite some entries, which also exercises the old-entry release
+    // path in `put`.
+    for k in 0..5 {
+        cache.put(&k, &((k as u64) + 100)).expect("failed writing to cache.");
+    }
+
+    let tmp_files: Vec<_> = all_file_names(tempdir.as_ref())
+        .into_iter()
+        .filter(|name| name.ends_with(".tmp"))
+        .collect();
+    assert!(tmp_files.is_empty(), "leftover tmp files: {:?}", tmp_files);
+}
+
+fn config_with_strategy(strategy: CacheStrategy) -> CacheConfig {
+    CacheConfig {
+        max_bytes: 10 * 1024 * 1024,
+        encoding: DataEncoding::Json,
+        strategy,
+        subdirs_per_level: 3,
+        ttl: None,
+        compression: Compression::None,
+        on_version_mismatch: MismatchPolicy::Clear,
+    }
+}
+
+/// A changed `strategy` changes `strategy_tag`, so reopening with a
+/// different one is indistinguishable from an incompatible format
+/// version as far as `Metadata::is_compatible_with` is concerned.
+#[test]
+fn version_mismatch_with_fail_policy_returns_an_error() {
+    let tempdir = get_tempdir("mismatch_fail");
+    let mut cache =
+        TestCache::initialize(tempdir.as_ref(), config_with_strategy(CacheStrategy::LRU))
+            .expect("failed initializing cache.");
+    cache.put(&1, &2).expect("failed writing to cache.");
+    drop(cache);
+
+    let mut config = config_with_strategy(CacheStrategy::FIFO);
+    config.on_version_mismatch = MismatchPolicy::Fail;
+    match TestCache::initialize(tempdir.as_ref(), config) {
+        Err(CacheError::VersionMismatch) => {}
+        Err(e) => panic!("expected CacheError::VersionMismatch, got {:?}", e),
+        Ok(_) => panic!("expected CacheError::VersionMismatch, got Ok"),
+    }
+}
+
+#[test]
+fn version_mismatch_with_clear_policy_discards_the_stale_cache() {
+    let tempdir = get_tempdir("mismatch_clear");
+    let mut cache =
+        TestCache::initialize(tempdir.as_ref(), config_with_strategy(CacheStrategy::LRU))
+            .expect("failed initializing cache.");
+    cache.put(&1, &2).expect("failed writing to cache.");
+    drop(cache);
+
+    let mut config = config_with_strategy(CacheStrategy::FIFO);
+    config.on_version_mismatch = MismatchPolicy::Clear;
+    let mut cache = TestCache::initialize(tempdir.as_ref(), config)
+        .expect("failed initializing cache after a Clear mismatch.");
+    assert_eq!(cache.get(&1).expect("failed reading from cache."), None);
+}
+
+fn compression_roundtrip(compression: Compression) {
+    let tempdir = get_tempdir("compression_roundtrip");
+    let config = CacheConfig {
+        max_bytes: 10 * 1024 * 1024,
+        encoding: DataEncoding::Json,
+        strategy: CacheStrategy::LRU,
+        subdirs_per_level: 3,
+        ttl: None,
+        compression,
+        on_version_mismatch: MismatchPolicy::Clear,
+    };
+    let mut cache =
+        TestCache::initialize(tempdir.as_ref(), config).expect("failed initializing cache.");
+
+    for k in 0..10 {
+        cache.put(&k, &(k as u64 * 2)).expect("failed writing to cache.");
+    }
+    for k in 0..10 {
+        let v = cache.get(&k).expect("failed reading from cache.");
+        assert_eq!(v, Some(k as u64 * 2));
+    }
+}
+
+#[test]
+fn compression_roundtrip_zstd() {
+    compression_roundtrip(Compression::Zstd { level: 3 })
+}
+
+#[test]
+fn compression_roundtrip_gzip() {
+    compression_roundtrip(Compression::Gzip)
+}
+
+/// A `ttl` of zero makes every entry expirable the moment it's written,
+/// since `is_expired` checks `elapsed >= ttl`. Used below to exercise
+/// expiry without needing to actually wait out a real TTL.
+fn ttl_config() -> CacheConfig {
+    CacheConfig {
+        max_bytes: 10 * 1024 * 1024,
+        encoding: DataEncoding::Json,
+        strategy: CacheStrategy::LRU,
+        subdirs_per_level: 3,
+        ttl: Some(Duration::from_secs(0)),
+        compression: Compression::None,
+        on_version_mismatch: MismatchPolicy::Clear,
+    }
+}
+
+#[test]
+fn get_on_expired_entry_is_a_miss() {
+    let tempdir = get_tempdir("ttl_get");
+    let mut cache = TestCache::initialize(tempdir.as_ref(), ttl_config())
+        .expect("failed initializing cache.");
+
+    cache.put(&1, &2).expect("failed writing to cache.");
+    assert_eq!(cache.get(&1).expect("failed reading from cache."), None);
+}
+
+#[test]
+fn purge_expired_removes_stale_entries_and_reports_the_count() {
+    let tempdir = get_tempdir("ttl_purge");
+    let mut cache = TestCache::initialize(tempdir.as_ref(), ttl_config())
+        .expect("failed initializing cache.");
+
+    for k in 0..5 {
+        cache.put(&k, &(k as u64)).expect("failed writing to cache.");
+    }
+
+    let purged = cache.purge_expired().expect("failed purging expired entries.");
+    assert_eq!(purged, 5);
+
+    // A second pass finds nothing left to purge.
+    let purged_again = cache.purge_expired().expect("failed purging expired entries.");
+    assert_eq!(purged_again, 0);
+
+    for k in 0..5 {
+        assert_eq!(cache.get(&k).expect("failed reading from cache."), None);
+    }
+}
+
+#[test]
+fn entries_do_not_expire_without_a_ttl() {
+    let tempdir = get_tempdir("no_ttl");
+    let mut config = ttl_config();
+    config.ttl = None;
+    let mut cache =
+        TestCache::initialize(tempdir.as_ref(), config).expect("failed initializing cache.");
+
+    cache.put(&1, &2).expect("failed writing to cache.");
+    assert_eq!(cache.get(&1).expect("failed reading from cache."), Some(2));
+    assert_eq!(
+        cache.purge_expired().expect("failed purging expired entries."),
+        0
+    );
+}